@@ -5,11 +5,15 @@ use std::any::{Any, TypeId};
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::collections::hash_map;
-use std::hash::Hash;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
 use std::intrinsics::needs_drop;
+use std::marker::PhantomData;
 use std::mem::{align_of, size_of};
 use std::ptr;
 
+pub use std::collections::TryReserveError;
+
 fn align(offset: usize, alignment: usize) -> usize {
     match offset % alignment {
         0 => offset,
@@ -51,12 +55,17 @@ fn align(offset: usize, alignment: usize) -> usize {
 /// can be used to reserve a larger buffer ahead of time to prevent expensive
 /// reallocation and move operations.
 ///
+/// The key lookup table is a `HashMap` parameterized by a `BuildHasher` `S`,
+/// defaulting to `RandomState` just like `std`'s `HashMap`. Use `with_hasher`
+/// or `with_capacity_and_hasher` to plug in a faster hasher for workloads
+/// that don't need hash-flooding resistance.
+///
 #[derive(Default)]
-pub struct PolyMap<K: Eq + Hash> {
+pub struct PolyMap<K: Eq + Hash, S: BuildHasher = RandomState> {
     /// Value data store
     data: Vec<u8>,
     /// Maps key to field offset
-    field_map: HashMap<K, usize>,
+    field_map: HashMap<K, usize, S>,
     /// Inserted fields, sorted by offset
     fields: Vec<Field>,
 }
@@ -64,14 +73,17 @@ pub struct PolyMap<K: Eq + Hash> {
 /// Private `PolyMap` field descriptor.
 ///
 /// Contains the field size and offset, as well as `TypeId`,
-/// which is used to identify a type for successive operations, and `drop`,
+/// which is used to identify a type for successive operations, `drop`,
 /// which is used to call a destructor ("drop glue") when `PolyMap::clear`
-/// is called or a `PolyMap` instance goes out of scope.
+/// is called or a `PolyMap` instance goes out of scope, and `clone`,
+/// which is used to duplicate the value when `PolyMap::clone` is called.
 struct Field {
     offset: usize,
     size: usize,
+    align: usize,
     id: TypeId,
     drop: Option<fn(*const ())>,
+    clone: Option<fn(*const (), *mut ())>,
 }
 
 /// Drops the pointed-to value as `T`.
@@ -79,6 +91,14 @@ fn drop_ptr<T>(p: *const ()) {
     unsafe { ptr::read(p as *const T); }
 }
 
+/// Clones the pointed-to value as `T`, writing the clone to `dst`.
+fn clone_ptr<T: Clone>(src: *const (), dst: *mut ()) {
+    unsafe {
+        let value = (&*(src as *const T)).clone();
+        ptr::write(dst as *mut T, value);
+    }
+}
+
 impl<K: Eq + Hash> PolyMap<K> {
     /// Constructs a new `PolyMap`.
     pub fn new() -> PolyMap<K> {
@@ -98,6 +118,28 @@ impl<K: Eq + Hash> PolyMap<K> {
             fields: Vec::with_capacity(n),
         }
     }
+}
+
+impl<K: Eq + Hash, S: BuildHasher> PolyMap<K, S> {
+    /// Constructs a new `PolyMap` that will use the given hasher to hash
+    /// keys.
+    pub fn with_hasher(hasher: S) -> PolyMap<K, S> {
+        PolyMap{
+            data: Vec::new(),
+            field_map: HashMap::with_hasher(hasher),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Constructs a new `PolyMap` with space reserved for `n` fields and
+    /// `size` bytes of data, that will use the given hasher to hash keys.
+    pub fn with_capacity_and_hasher(n: usize, size: usize, hasher: S) -> PolyMap<K, S> {
+        PolyMap{
+            data: Vec::with_capacity(size),
+            field_map: HashMap::with_capacity_and_hasher(n, hasher),
+            fields: Vec::with_capacity(n),
+        }
+    }
 
     /// Removes all key-value pairs from the map, calling any destructors on
     /// stored values.
@@ -173,6 +215,37 @@ impl<K: Eq + Hash> PolyMap<K> {
             .map(|offset| unsafe { &mut *self.get_data_mut(offset) })
     }
 
+    /// Returns a reference to the value corresponding to the given key,
+    /// without checking that the stored type matches `T`.
+    ///
+    /// If the key is not contained within the map, `None` will be returned.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that any value stored for `k` was inserted
+    /// as `T`; unlike `get`, the `TypeId` comparison is skipped, so a
+    /// mismatched `T` reinterprets the stored bytes instead of panicking.
+    pub unsafe fn get_unchecked<Q: ?Sized, T: Any>(&self, k: &Q) -> Option<&T>
+            where K: Borrow<Q>, Q: Eq + Hash {
+        self.get_field(k).map(|f| &*self.get_data(f.offset))
+    }
+
+    /// Returns a mutable reference to the value corresponding to the given
+    /// key, without checking that the stored type matches `T`.
+    ///
+    /// If the key is not contained within the map, `None` will be returned.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that any value stored for `k` was inserted
+    /// as `T`; unlike `get_mut`, the `TypeId` comparison is skipped, so a
+    /// mismatched `T` reinterprets the stored bytes instead of panicking.
+    pub unsafe fn get_unchecked_mut<Q: ?Sized, T: Any>(&mut self, k: &Q) -> Option<&mut T>
+            where K: Borrow<Q>, Q: Eq + Hash {
+        self.get_field(k).map(|f| f.offset)
+            .map(|offset| &mut *self.get_data_mut(offset))
+    }
+
     /// Inserts a key-value pair into the map. If the key is already present,
     /// that value is returned. Otherwise, `None` is returned.
     ///
@@ -191,13 +264,69 @@ impl<K: Eq + Hash> PolyMap<K> {
             if let Some(offset) = offset {
                 Some(ptr::replace(self.get_data_mut(offset), t))
             } else {
-                let offset = self.allocate::<T>(k);
+                let offset = self.allocate::<T>(k, None);
+                ptr::write(self.get_data_mut(offset), t);
+                None
+            }
+        }
+    }
+
+    /// Inserts a key-value pair into the map, additionally registering clone
+    /// glue for the value so that a `PolyMap` containing it can later be
+    /// duplicated via `Clone`. If the key is already present, that value is
+    /// returned. Otherwise, `None` is returned.
+    ///
+    /// # Panics
+    ///
+    /// If the key exists, but has a value of a different type than the one given.
+    pub fn insert_clone<T: Any + Clone>(&mut self, k: K, t: T) -> Option<T> {
+        let offset = self.get_field(&k).map(|f| {
+            if f.id != TypeId::of::<T>() {
+                panic!("insert with value of different type");
+            }
+            f.offset
+        });
+
+        unsafe {
+            if let Some(offset) = offset {
+                let pos = self.fields.binary_search_by(|f| f.offset.cmp(&offset)).unwrap();
+                self.fields[pos].clone = Some(clone_ptr::<T>);
+                Some(ptr::replace(self.get_data_mut(offset), t))
+            } else {
+                let offset = self.allocate::<T>(k, Some(clone_ptr::<T>));
                 ptr::write(self.get_data_mut(offset), t);
                 None
             }
         }
     }
 
+    /// Gets the given key's corresponding entry in the map for in-place
+    /// manipulation, resolving the key's field offset (if any) only once.
+    ///
+    /// # Panics
+    ///
+    /// If the key exists, but has a value of a different type than `T`.
+    pub fn entry<T: Any>(&mut self, key: K) -> Entry<K, T, S> {
+        let offset = self.field_map.get(&key).map(|&off| off).map(|offset| {
+            let pos = self.fields.binary_search_by(|f| f.offset.cmp(&offset)).unwrap();
+            if self.fields[pos].id != TypeId::of::<T>() {
+                panic!("entry with value of different type");
+            }
+            offset
+        });
+
+        match offset {
+            Some(offset) => Entry::Occupied(OccupiedEntry{
+                value: unsafe { &mut *self.get_data_mut(offset) },
+            }),
+            None => Entry::Vacant(VacantEntry{
+                key: key,
+                map: self,
+                marker: PhantomData,
+            }),
+        }
+    }
+
     /// Returns an iterator visiting all keys in arbitrary order.
     /// Iterator element type is `&K`.
     pub fn keys(&self) -> Keys<K> {
@@ -232,6 +361,20 @@ impl<K: Eq + Hash> PolyMap<K> {
         self.fields.reserve_exact(n);
     }
 
+    /// Tries to reserve capacity for at least `additional` additional bytes
+    /// of storage space within the internal data buffer, returning an error
+    /// instead of aborting if the allocation fails.
+    pub fn try_reserve_data(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.data.try_reserve(additional)
+    }
+
+    /// Tries to reserve capacity for at least `additional` additional
+    /// fields, returning an error instead of aborting if the allocation
+    /// fails.
+    pub fn try_reserve_fields(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.fields.try_reserve(additional)
+    }
+
     /// Removes a key from the map, returning the value if one existed.
     ///
     /// # Panics
@@ -257,16 +400,77 @@ impl<K: Eq + Hash> PolyMap<K> {
         })
     }
 
+    /// Removes a key from the map without regard to the type of its value,
+    /// returning whether anything was removed.
+    ///
+    /// Unlike `remove`, this never reads the value out as a typed `T`;
+    /// instead it invokes the field's stored drop glue (the same glue
+    /// `clear` uses), so it can be called even when the caller doesn't
+    /// know which concrete type was inserted for this key.
+    pub fn remove_any<Q: ?Sized>(&mut self, k: &Q) -> bool
+            where K: Borrow<Q>, Q: Eq + Hash {
+        let pos = self.get_offset(k).map(|offset|
+            self.fields.binary_search_by(|f| f.offset.cmp(&offset)).unwrap());
+
+        match pos {
+            Some(pos) => {
+                self.field_map.remove(k).unwrap();
+                let f = self.fields.remove(pos);
+                if let Some(dropper) = f.drop {
+                    dropper(self.get_data::<()>(f.offset));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Shrinks the internal data buffer as close as possible to the size of
-    /// the currently contained elements.
+    /// the currently contained elements, first condensing fields to
+    /// eliminate any holes left behind by removed fields.
     pub fn shrink_data_to_fit(&mut self) {
-        // TODO: Make an effort to condense elements within allocated space
+        self.compact();
+    }
+
+    /// Condenses the data buffer by moving each field as far down as its
+    /// alignment allows, eliminating gaps left by removed fields while
+    /// preserving the relative order of the fields that remain.
+    ///
+    /// Shrinks the data buffer to the resulting size once all fields have
+    /// been moved.
+    pub fn compact(&mut self) {
+        let mut write_cursor = 0;
+
+        for field in self.fields.iter_mut() {
+            let dest = align(write_cursor, field.align);
+
+            if dest < field.offset {
+                unsafe {
+                    let src = self.data.as_ptr().offset(field.offset as isize);
+                    let dst = self.data.as_mut_ptr().offset(dest as isize);
+                    ptr::copy(src, dst, field.size);
+                }
+
+                for offset in self.field_map.values_mut() {
+                    if *offset == field.offset {
+                        *offset = dest;
+                        break;
+                    }
+                }
+
+                field.offset = dest;
+            }
+
+            write_cursor = dest + field.size;
+        }
+
+        self.data.truncate(write_cursor);
         self.data.shrink_to_fit();
     }
 
     /// Allocates space for an object of given size and alignment.
     /// Grows buffer if necessary. Returns offset of new object.
-    fn allocate<T: Any>(&mut self, k: K) -> usize {
+    fn allocate<T: Any>(&mut self, k: K, clone: Option<fn(*const (), *mut ())>) -> usize {
         let id = TypeId::of::<T>();
 
         let (size, alignment) = match size_of::<T>() {
@@ -305,12 +509,14 @@ impl<K: Eq + Hash> PolyMap<K> {
         self.fields.insert(index, Field{
             offset: offset,
             size: size,
+            align: alignment,
             id: id,
             drop: if unsafe { needs_drop::<T>() } {
                 Some(drop_ptr::<T>)
             } else {
                 None
             },
+            clone: clone,
         });
 
         offset
@@ -358,12 +564,107 @@ impl<K: Eq + Hash> PolyMap<K> {
     }
 }
 
-impl<K: Eq + Hash> Drop for PolyMap<K> {
+impl<K: Eq + Hash, S: BuildHasher> Drop for PolyMap<K, S> {
     fn drop(&mut self) {
         self.clear();
     }
 }
 
+impl<K: Eq + Hash + Clone, S: BuildHasher + Clone> Clone for PolyMap<K, S> {
+    /// Returns a copy of the map.
+    ///
+    /// # Panics
+    ///
+    /// If any stored value was inserted with plain `insert` rather than
+    /// `insert_clone`, it carries no clone glue and this panics rather
+    /// than silently dropping it or cloning garbage.
+    fn clone(&self) -> PolyMap<K, S> {
+        if self.fields.iter().any(|f| f.clone.is_none()) {
+            panic!("cannot clone a PolyMap holding a value with no clone glue");
+        }
+
+        let mut data = vec![0u8; self.data.len()];
+        let mut fields = Vec::with_capacity(self.fields.len());
+
+        for f in &self.fields {
+            let cloner = f.clone.unwrap();
+
+            unsafe {
+                let src = self.data.as_ptr().offset(f.offset as isize) as *const ();
+                let dst = data.as_mut_ptr().offset(f.offset as isize) as *mut ();
+                cloner(src, dst);
+            }
+
+            fields.push(Field{
+                offset: f.offset,
+                size: f.size,
+                align: f.align,
+                id: f.id,
+                drop: f.drop,
+                clone: f.clone,
+            });
+        }
+
+        PolyMap{
+            data: data,
+            field_map: self.field_map.clone(),
+            fields: fields,
+        }
+    }
+}
+
+/// A view into a single entry in a `PolyMap`, which may either be vacant or
+/// occupied.
+///
+/// This is constructed from the `entry` method on `PolyMap`.
+pub enum Entry<'a, K: 'a + Eq + Hash, T: 'a, S: 'a + BuildHasher = RandomState> {
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, T>),
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, T, S>),
+}
+
+/// A view into an occupied entry in a `PolyMap`.
+pub struct OccupiedEntry<'a, T: 'a> {
+    value: &'a mut T,
+}
+
+impl<'a, T> OccupiedEntry<'a, T> {
+    /// Returns a reference to the entry's value.
+    pub fn get(&self) -> &T {
+        self.value
+    }
+
+    /// Returns a mutable reference to the entry's value.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value
+    }
+
+    /// Consumes the entry, returning a mutable reference to its value with
+    /// the lifetime of the map.
+    pub fn into_mut(self) -> &'a mut T {
+        self.value
+    }
+}
+
+/// A view into a vacant entry in a `PolyMap`.
+pub struct VacantEntry<'a, K: 'a + Eq + Hash, T: 'a, S: 'a + BuildHasher = RandomState> {
+    key: K,
+    map: &'a mut PolyMap<K, S>,
+    marker: PhantomData<T>,
+}
+
+impl<'a, K: Eq + Hash, T: Any, S: BuildHasher> VacantEntry<'a, K, T, S> {
+    /// Sets the value of the entry, returning a mutable reference to it.
+    pub fn insert(self, value: T) -> &'a mut T {
+        let offset = self.map.allocate::<T>(self.key, None);
+        unsafe {
+            ptr::write(self.map.get_data_mut(offset), value);
+            &mut *self.map.get_data_mut(offset)
+        }
+    }
+}
+
 /// Iterator over the keys of a `PolyMap`
 #[derive(Clone)]
 pub struct Keys<'a, K: 'a> {
@@ -581,4 +882,156 @@ mod tests {
 
         assert!(aptr != bptr && bptr != cptr);
     }
+
+    #[test]
+    fn test_entry() {
+        use super::Entry;
+
+        let mut map = PolyMap::new();
+
+        match map.entry::<u32>("a") {
+            Entry::Vacant(e) => { *e.insert(1) += 9; }
+            Entry::Occupied(_) => panic!("expected vacant entry"),
+        }
+        assert_eq!(map.get("a"), Some(&10_u32));
+
+        match map.entry::<u32>("a") {
+            Entry::Occupied(mut e) => { *e.get_mut() += 1; }
+            Entry::Vacant(_) => panic!("expected occupied entry"),
+        }
+        assert_eq!(map.get("a"), Some(&11_u32));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_entry_mismatch() {
+        let mut map = PolyMap::new();
+
+        map.insert("a", 1_u32);
+        let _ = map.entry::<i32>("a");
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut map = PolyMap::new();
+
+        map.insert_clone("a", 1_u32);
+        map.insert_clone("b", "foo".to_string());
+
+        let cloned = map.clone();
+
+        assert_eq!(cloned.get("a"), Some(&1_u32));
+        assert_eq!(cloned.get("b"), Some(&"foo".to_string()));
+
+        // The clone is an independent copy: mutating the original doesn't
+        // affect the clone.
+        *map.get_mut::<_, u32>("a").unwrap() = 2;
+        assert_eq!(map.get("a"), Some(&2_u32));
+        assert_eq!(cloned.get("a"), Some(&1_u32));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_clone_without_glue_panics() {
+        let mut map = PolyMap::new();
+
+        // Inserted via plain `insert`, so no clone glue was registered.
+        map.insert("a", 1_u32);
+        let _ = map.clone();
+    }
+
+    #[test]
+    fn test_with_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let mut map: PolyMap<&str, BuildHasherDefault<DefaultHasher>> =
+            PolyMap::with_hasher(BuildHasherDefault::default());
+
+        map.insert("a", 1_u32);
+        map.insert("b", 2_u32);
+
+        assert_eq!(map.get("a"), Some(&1_u32));
+        assert_eq!(map.get("b"), Some(&2_u32));
+
+        let mut map: PolyMap<&str, BuildHasherDefault<DefaultHasher>> =
+            PolyMap::with_capacity_and_hasher(4, 16, BuildHasherDefault::default());
+
+        map.insert("c", 3_u32);
+        assert_eq!(map.get("c"), Some(&3_u32));
+        assert_eq!(map.get::<_, u32>("a"), None);
+    }
+
+    #[test]
+    fn test_try_reserve() {
+        let mut map: PolyMap<&str> = PolyMap::new();
+
+        assert!(map.try_reserve_data(64).is_ok());
+        assert!(map.data_capacity() >= 64);
+
+        assert!(map.try_reserve_fields(8).is_ok());
+
+        // Reserving zero additional capacity is a harmless no-op.
+        assert!(map.try_reserve_data(0).is_ok());
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut map = PolyMap::new();
+
+        map.insert("a", 0xAAAAAAAA_u32);
+        map.insert("b", 0xBBBBBBBB_u32);
+        map.insert("c", 0xCCCCCCCC_u32);
+
+        map.remove::<_, u32>("b");
+        assert_eq!(map.data_size(), 12);
+
+        map.compact();
+
+        // Compacting must not disturb the still-live values, and the hole
+        // left by "b" should be gone from the data buffer.
+        assert_eq!(map.get("a"), Some(&0xAAAAAAAA_u32));
+        assert_eq!(map.get("c"), Some(&0xCCCCCCCC_u32));
+        assert_eq!(map.data_size(), 8);
+
+        // The field_map must have been kept consistent with the moved
+        // field, so further operations on "c" still work.
+        map.insert("d", 0xDDDDDDDD_u32);
+        assert_eq!(map.get("c"), Some(&0xCCCCCCCC_u32));
+        assert_eq!(map.get("d"), Some(&0xDDDDDDDD_u32));
+    }
+
+    #[test]
+    fn test_remove_any() {
+        DROP_COUNT.store(0, SeqCst);
+
+        let mut map = PolyMap::new();
+        map.insert("a", Dropper{n: 5});
+        map.insert("b", 1_u32);
+
+        assert!(map.remove_any("a"));
+        assert_eq!(DROP_COUNT.load(SeqCst), 5);
+        assert!(!map.contains_key("a"));
+
+        assert!(map.remove_any("b"));
+        assert!(!map.contains_key("b"));
+
+        // Removing a key that isn't present is a no-op that returns false.
+        assert!(!map.remove_any("a"));
+    }
+
+    #[test]
+    fn test_get_unchecked() {
+        let mut map = PolyMap::new();
+        map.insert("a", 0xAAAAAAAA_u32);
+
+        unsafe {
+            assert_eq!(map.get_unchecked::<_, u32>("a"), Some(&0xAAAAAAAA_u32));
+            assert_eq!(map.get_unchecked::<_, u32>("b"), None);
+
+            *map.get_unchecked_mut::<_, u32>("a").unwrap() = 0xBBBBBBBB;
+        }
+
+        assert_eq!(map.get("a"), Some(&0xBBBBBBBB_u32));
+    }
 }